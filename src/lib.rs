@@ -1,10 +1,12 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, UnorderedMap, Vector};
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
 use near_sdk::{
-    env, ext_contract, near_bindgen, AccountId, Balance, BorshStorageKey, Gas, PanicOnDefault,
-    Promise, PromiseOrValue, PromiseResult,
+    env, ext_contract, near_bindgen, AccountId, Balance, BorshStorageKey, CryptoHash, Gas,
+    PanicOnDefault, Promise, PromiseOrValue, PromiseResult,
 };
 
 mod order;
@@ -12,52 +14,234 @@ use order::*;
 
 pub type OrderId = String;
 pub const TRANSFER_GAS: Gas = Gas(10_000_000_000_000);
+pub const FT_TRANSFER_GAS: Gas = Gas(10_000_000_000_000);
+// Thoi gian mac dinh nguoi mua co the tranh chap truoc khi bat ky ai cung claim duoc refund
+pub const DEFAULT_DISPUTE_WINDOW_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 #[near_bindgen]
 struct EcommerceContract {
     pub owner_id: AccountId,
-    pub orders: LookupMap<OrderId, Order>,
+    pub orders: UnorderedMap<OrderId, Order>,
+    // So NEAR moi account da nap de tra phi luu tru (NEP-145)
+    pub storage_balances: LookupMap<AccountId, Balance>,
+    // Phi luu tru da tru cua tung order, dung de hoan lai khi refund
+    pub order_storage_cost: LookupMap<OrderId, Balance>,
+    // Chi muc order_id theo tung payer, phuc vu get_orders_by_payer
+    pub orders_by_payer: LookupMap<AccountId, Vector<OrderId>>,
+    // Phi nen tang cua platform, tinh theo basis points (1/10_000) tren order_amount
+    pub fee_bps: u16,
+    pub fee_account_id: AccountId,
+    // Chinh sach: khi refund, co tra lai ca phan fee cho payer hay khong
+    pub fee_refundable: bool,
+    // Danh sach FT token_id duoc owner cho phep goi ft_on_transfer, tranh gia mao predecessor
+    pub allowed_token_ids: LookupMap<AccountId, bool>,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, BorshStorageKey)]
 enum StorageKey {
     OrderKey,
+    StorageBalanceKey,
+    OrderStorageCostKey,
+    OrdersByPayerKey,
+    OrdersByPayerInnerKey { account_hash: CryptoHash },
+    AllowedTokenIdsKey,
 }
 
 #[ext_contract(ext_self)]
 pub trait ExtEcommerceContract {
-    fn transfer_callback(&mut self, order_id: OrderId) -> PromiseOrValue<U128>;
+    fn transfer_callback(&mut self, order_id: OrderId, refunded_delta: U128) -> PromiseOrValue<U128>;
+    fn release_callback(&mut self, order_id: OrderId) -> PromiseOrValue<U128>;
+    fn fee_callback(&mut self, order_id: OrderId);
+}
+
+#[ext_contract(ext_fungible_token)]
+pub trait ExtFungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
 }
 #[near_bindgen]
 impl EcommerceContract {
     #[init]
     pub fn new(owner_id: AccountId) -> Self {
         Self {
-            owner_id,
-            orders: LookupMap::new(StorageKey::OrderKey),
+            owner_id: owner_id.clone(),
+            orders: UnorderedMap::new(StorageKey::OrderKey),
+            storage_balances: LookupMap::new(StorageKey::StorageBalanceKey),
+            order_storage_cost: LookupMap::new(StorageKey::OrderStorageCostKey),
+            orders_by_payer: LookupMap::new(StorageKey::OrdersByPayerKey),
+            fee_bps: 0,
+            fee_account_id: owner_id,
+            fee_refundable: false,
+            allowed_token_ids: LookupMap::new(StorageKey::AllowedTokenIdsKey),
+        }
+    }
+
+    // Owner cau hinh muc phi nen tang va noi nhan phi
+    pub fn set_fee(&mut self, fee_bps: u16, fee_account_id: AccountId) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "ERROR_NOT_AUTHORIZED"
+        );
+        assert!(fee_bps <= 10_000, "ERROR_FEE_BPS_TOO_HIGH");
+
+        self.fee_bps = fee_bps;
+        self.fee_account_id = fee_account_id;
+    }
+
+    // Owner cau hinh chinh sach: refund co hoan lai ca phan fee cho payer hay khong
+    pub fn set_fee_refundable(&mut self, fee_refundable: bool) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "ERROR_NOT_AUTHORIZED"
+        );
+
+        self.fee_refundable = fee_refundable;
+    }
+
+    // Owner them/bo mot FT contract khoi danh sach duoc phep goi ft_on_transfer
+    pub fn set_token_allowed(&mut self, token_id: AccountId, allowed: bool) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "ERROR_NOT_AUTHORIZED"
+        );
+
+        self.allowed_token_ids.insert(&token_id, &allowed);
+    }
+
+    // Bam AccountId thanh CryptoHash de lam prefix rieng cho Vector con cua tung payer
+    fn hash_account_id(account_id: &AccountId) -> CryptoHash {
+        let mut hash = CryptoHash::default();
+        hash.copy_from_slice(&env::sha256(account_id.as_bytes()));
+        hash
+    }
+
+    // Ghi nhan order_id vao chi muc cua payer, tao Vector moi neu day la order dau tien
+    fn index_order_by_payer(&mut self, payer_id: &AccountId, order_id: &OrderId) {
+        let mut order_ids = self.orders_by_payer.get(payer_id).unwrap_or_else(|| {
+            Vector::new(StorageKey::OrdersByPayerInnerKey {
+                account_hash: Self::hash_account_id(payer_id),
+            })
+        });
+        order_ids.push(order_id);
+        self.orders_by_payer.insert(payer_id, &order_ids);
+    }
+
+    // Xoa hang order da Refunded va chi muc cua no khoi orders_by_payer, de storage
+    // thuc su duoc giai phong sau khi da hoan tien cho payer (khop voi storage
+    // rent da release_storage_cost). Chi goi sau khi chuyen tien hoan da thanh cong.
+    fn prune_order(&mut self, order_id: &OrderId, payer_id: &AccountId) {
+        self.orders.remove(order_id);
+
+        if let Some(mut order_ids) = self.orders_by_payer.get(payer_id) {
+            if let Some(index) = (0..order_ids.len()).find(|&i| &order_ids.get(i).unwrap() == order_id)
+            {
+                order_ids.swap_remove(index);
+                self.orders_by_payer.insert(payer_id, &order_ids);
+            }
+        }
+    }
+
+    // Dang ky / nap them tien de tra phi luu tru theo NEP-145
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) -> U128 {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let deposit = env::attached_deposit();
+        assert!(deposit > 0, "ERROR_DEPOSIT_NOT_ENOUGH");
+
+        let balance = self.storage_balances.get(&account_id).unwrap_or(0) + deposit;
+        self.storage_balances.insert(&account_id, &balance);
+
+        U128(balance)
+    }
+
+    // Rut lai phan storage balance chua dung den
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let balance = self
+            .storage_balances
+            .get(&account_id)
+            .expect("ERROR_STORAGE_NOT_REGISTERED");
+
+        let withdraw_amount = amount.map(|a| a.0).unwrap_or(balance);
+        assert!(
+            withdraw_amount <= balance,
+            "ERROR_STORAGE_BALANCE_NOT_ENOUGH"
+        );
+
+        let new_balance = balance - withdraw_amount;
+        self.storage_balances.insert(&account_id, &new_balance);
+
+        if withdraw_amount > 0 {
+            Promise::new(account_id).transfer(withdraw_amount);
         }
+
+        U128(new_balance)
     }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> U128 {
+        U128(self.storage_balances.get(&account_id).unwrap_or(0))
+    }
+
     #[payable] //cho phep user nap tien vao
-    pub fn pay_order(&mut self, order_id: OrderId, order_amount: U128) -> PromiseOrValue<U128> {
+    pub fn pay_order(
+        &mut self,
+        order_id: OrderId,
+        order_amount: U128,
+        seller_id: AccountId,
+    ) -> PromiseOrValue<U128> {
         // Lay thong tin so NEAR deposit cua user env::attached_deposit()
         assert!(
             env::attached_deposit() >= order_amount.0,
             "ERROR_DEPOSIT_NOT_ENOUGH"
         );
+        assert!(
+            self.orders.get(&order_id).is_none(),
+            "ERROR_ORDER_ALREADY_EXISTS"
+        );
+
+        let payer_id = env::signer_account_id();
+        let storage_usage_before = env::storage_usage();
+        let fee = order_amount.0 * self.fee_bps as u128 / 10_000;
 
-        // Luu tru thong tin thanh toan cua user
+        // Luu tru thong tin thanh toan cua user, tien giu trong contract cho den khi
+        // nguoi mua confirm_delivery hoac het han dispute_window_ns
         let order: Order = Order {
             order_id: order_id.clone(),
-            payer_id: env::signer_account_id(),
+            payer_id: payer_id.clone(),
+            seller_id,
             amount: order_amount.0,
             received_amount: env::attached_deposit(),
-            is_completed: true,
-            is_refund: false,
+            fee,
+            refunded_amount: 0,
+            // Chup chinh sach hien tai, de set_fee_refundable() sau nay khong anh huong
+            // hoi to toi cac don hang da thanh toan tu truoc
+            fee_refundable: self.fee_refundable,
+            fee_settled: fee == 0,
+            status: OrderStatus::Pending,
             created_at: env::block_timestamp(),
+            dispute_window_ns: DEFAULT_DISPUTE_WINDOW_NS,
+            token_id: None,
         };
 
         self.orders.insert(&order_id, &order);
+        self.index_order_by_payer(&payer_id, &order_id);
+        self.charge_storage_cost(&order_id, &payer_id, storage_usage_before);
+
+        // Phi nen tang duoc tru va chuyen cho platform ngay tai thoi diem thanh toan,
+        // khong cho den luc confirm_delivery/refund moi gui. Giong cac luong chuyen tien
+        // khac trong file nay, chain callback de cap nhat fee_settled thay vi fire-and-forget.
+        if fee > 0 {
+            let fee_promise = Promise::new(self.fee_account_id.clone()).transfer(fee);
+            fee_promise.then(
+                ext_self::ext(env::current_account_id())
+                    .with_attached_deposit(0)
+                    .with_static_gas(TRANSFER_GAS)
+                    .fee_callback(order_id.clone()),
+            );
+        }
 
         // Tra lai tien thua cho user
         if env::attached_deposit() > order_amount.0 {
@@ -68,64 +252,337 @@ impl EcommerceContract {
         PromiseOrValue::Value(U128(0))
     }
 
+    // Tru phi luu tru (NEP-145) cua payer dua tren so byte storage thuc te da tang them.
+    // Goi sau khi TAT CA cac map lien quan (orders, orders_by_payer, ...) da duoc ghi,
+    // de khong bo sot byte nao; ban than viec ghi order_storage_cost cung ton byte nen
+    // duoc tinh bu o lan do thu hai.
+    fn charge_storage_cost(
+        &mut self,
+        order_id: &OrderId,
+        payer_id: &AccountId,
+        storage_usage_before: u64,
+    ) {
+        let bytes_used = env::storage_usage().saturating_sub(storage_usage_before);
+        let mut cost = Balance::from(bytes_used) * env::storage_byte_cost();
+        self.deduct_storage_balance(payer_id, cost);
+        self.order_storage_cost.insert(order_id, &cost);
+
+        // Ban ghi order_storage_cost vua insert cung chiem storage, do bu them o day
+        let extra_bytes = env::storage_usage().saturating_sub(storage_usage_before + bytes_used);
+        if extra_bytes > 0 {
+            let extra_cost = Balance::from(extra_bytes) * env::storage_byte_cost();
+            self.deduct_storage_balance(payer_id, extra_cost);
+            cost += extra_cost;
+            self.order_storage_cost.insert(order_id, &cost);
+        }
+    }
+
+    fn deduct_storage_balance(&mut self, payer_id: &AccountId, cost: Balance) {
+        let payer_balance = self
+            .storage_balances
+            .get(payer_id)
+            .expect("ERROR_STORAGE_NOT_REGISTERED");
+        assert!(payer_balance >= cost, "ERROR_STORAGE_BALANCE_NOT_ENOUGH");
+
+        self.storage_balances
+            .insert(payer_id, &(payer_balance - cost));
+    }
+
+    // Hoan lai phi luu tru da tru cua mot order ve storage balance cua payer
+    fn release_storage_cost(&mut self, order_id: &OrderId, payer_id: &AccountId) {
+        if let Some(cost) = self.order_storage_cost.get(order_id) {
+            let balance = self.storage_balances.get(payer_id).unwrap_or(0);
+            self.storage_balances.insert(payer_id, &(balance + cost));
+            self.order_storage_cost.remove(order_id);
+        }
+    }
+
     // Trả lại data cho user thong qua DTOs -> Data Transfer Object
     pub fn get_order(&self, order_id: OrderId) -> Order {
         self.orders.get(&order_id).expect("NOT_FOUND_ORDER_ID")
     }
 
-    // Refund lai tien cho user
+    // Liet ke order cho dashboard cua owner, phan trang theo index trong UnorderedMap
+    pub fn get_orders(&self, from_index: U64, limit: U64) -> Vec<Order> {
+        let values = self.orders.values_as_vector();
+        let from = from_index.0;
+        let to = std::cmp::min(from + limit.0, values.len());
+
+        (from..to).map(|index| values.get(index).unwrap()).collect()
+    }
+
+    // Liet ke lich su don hang cua mot payer, phan trang theo chi muc rieng cua payer do
+    pub fn get_orders_by_payer(
+        &self,
+        payer_id: AccountId,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<Order> {
+        let order_ids = match self.orders_by_payer.get(&payer_id) {
+            Some(order_ids) => order_ids,
+            None => return vec![],
+        };
+
+        let from = from_index.0;
+        let to = std::cmp::min(from + limit.0, order_ids.len());
+
+        (from..to)
+            .filter_map(|index| order_ids.get(index))
+            .filter_map(|order_id| self.orders.get(&order_id))
+            .collect()
+    }
+
+    // Refund lai tien cho user, co the refund tung phan qua nhieu lan goi
     /**
      * Kiem tra xem nguoi goi co phai la owner cuar contract khong?
-     * Kiem xem don hang da complete va refund chua?
-     * Thuc hien viec cap nhat trang thai don + tra tien cho user
+     * Kiem xem don hang co dang Pending khong?
+     * amount = None nghia la hoan not phan con lai, Some(x) la hoan dung x
      */
-
-    pub fn refund(&mut self, order_id: OrderId) -> PromiseOrValue<U128> {
+    pub fn refund(&mut self, order_id: OrderId, amount: Option<U128>) -> PromiseOrValue<U128> {
         //Kiem tra xem nguoi goi co phai la owner cuar contract khong?
         assert_eq!(env::predecessor_account_id(), self.owner_id);
 
         // get order dang muon refund
+        let order = self.orders.get(&order_id).expect("ERROR_NOT_FOUND_ORDER");
+        assert_eq!(order.status, OrderStatus::Pending, "ERROR_INVALID_ORDER_STATUS");
+
+        self.process_refund(order_id, order, amount.map(|a| a.0))
+    }
+
+    // Nguoi mua (hoac owner) xac nhan da nhan hang, giai ngan tien cho seller_id
+    pub fn confirm_delivery(&mut self, order_id: OrderId) -> PromiseOrValue<U128> {
+        let caller = env::predecessor_account_id();
         let mut order = self.orders.get(&order_id).expect("ERROR_NOT_FOUND_ORDER");
+        assert!(
+            caller == order.payer_id || caller == self.owner_id,
+            "ERROR_NOT_AUTHORIZED"
+        );
+        assert_eq!(order.status, OrderStatus::Pending, "ERROR_INVALID_ORDER_STATUS");
+
+        order.status = OrderStatus::Released;
+        self.orders.insert(&order_id, &order);
+
+        // Phi nen tang da duoc chuyen cho platform tu luc pay_order/ft_on_transfer,
+        // seller chi nhan phan con lai (net_amount)
+        let net_amount = order.amount - order.fee;
+
+        if net_amount == 0 {
+            return PromiseOrValue::Value(U128(0));
+        }
 
-        // don hang da hoan thanh va chua refund
-        assert!(order.is_completed && !order.is_refund);
-        // let order = self.orders.find(order_id);
+        let promise: Promise = match &order.token_id {
+            // Don hang tra bang FT -> goi ft_transfer toi token contract
+            Some(token_id) => ext_fungible_token::ext(token_id.clone())
+                .with_attached_deposit(1)
+                .with_static_gas(FT_TRANSFER_GAS)
+                .ft_transfer(order.seller_id.clone(), U128(net_amount), None),
+            // Don hang tra bang native NEAR
+            None => Promise::new(order.seller_id.clone()).transfer(net_amount),
+        };
+
+        let promise = promise.then(
+            ext_self::ext(env::current_account_id())
+                .with_attached_deposit(0)
+                .with_static_gas(TRANSFER_GAS)
+                .release_callback(order_id),
+        );
+        PromiseOrValue::Promise(promise)
+    }
 
-        order.is_refund = true;
+    // Bat ky ai cung co the goi khi het dispute_window_ns de tra tien ve cho payer
+    pub fn claim_timeout_refund(&mut self, order_id: OrderId) -> PromiseOrValue<U128> {
+        let order = self.orders.get(&order_id).expect("ERROR_NOT_FOUND_ORDER");
+        assert_eq!(order.status, OrderStatus::Pending, "ERROR_INVALID_ORDER_STATUS");
+        assert!(
+            env::block_timestamp() > order.created_at + order.dispute_window_ns,
+            "ERROR_DISPUTE_WINDOW_NOT_EXPIRED"
+        );
 
-        // cap nhat trang thai don va ghi de toan bo order moi lene order_id cu
+        self.process_refund(order_id, order, None)
+    }
+
+    // Logic hoan tien dung chung cho refund() va claim_timeout_refund(); requested = None
+    // nghia la hoan not toan bo phan con lai cua don hang
+    fn process_refund(
+        &mut self,
+        order_id: OrderId,
+        mut order: Order,
+        requested: Option<Balance>,
+    ) -> PromiseOrValue<U128> {
+        // fee_refundable quyet dinh payer co the lay lai ca fee hay chi net amount.
+        // Phi da duoc chuyen cho platform tu luc pay_order/ft_on_transfer nen o day
+        // khong gui lai fee lan nua, chi tru no ra khoi so tien payer duoc nhan.
+        // Dung order.fee_refundable (chup tai thoi diem thanh toan) chu khong phai
+        // self.fee_refundable, de set_fee_refundable() sau nay khong anh huong hoi to.
+        let refundable_cap = if order.fee_refundable {
+            order.amount
+        } else {
+            order.amount - order.fee
+        };
+        let remaining = refundable_cap - order.refunded_amount;
+        let requested = requested.unwrap_or(remaining);
+        assert!(
+            order.refunded_amount + requested <= refundable_cap,
+            "ERROR_REFUND_EXCEEDS_REMAINING"
+        );
+
+        order.refunded_amount += requested;
+        if order.refunded_amount == refundable_cap {
+            // Khong release_storage_cost/prune_order o day: Promise chuyen tien ben duoi
+            // chua chay, neu that bai thi order van con nguyen ma storage da bi tra truoc
+            // do. Chi giai phong storage that su sau khi transfer_callback xac nhan thanh cong.
+            order.status = OrderStatus::Refunded;
+        }
         self.orders.insert(&order_id, &order);
 
-        // Tra tien cho user
-        // signer_account_id la vi goc cua admin
-        if order.amount > 0 {
-            // Cross contract call
-            let promise: Promise = Promise::new(order.payer_id).transfer(order.amount).then(
+        if requested == 0 {
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let promise: Promise = match &order.token_id {
+            // Don hang tra bang FT -> goi ft_transfer toi token contract
+            Some(token_id) => ext_fungible_token::ext(token_id.clone())
+                .with_attached_deposit(1)
+                .with_static_gas(FT_TRANSFER_GAS)
+                .ft_transfer(order.payer_id.clone(), U128(requested), None),
+            // Don hang tra bang native NEAR
+            None => Promise::new(order.payer_id.clone()).transfer(requested),
+        };
+
+        let promise = promise.then(
+            ext_self::ext(env::current_account_id())
+                .with_attached_deposit(0)
+                .with_static_gas(TRANSFER_GAS)
+                .transfer_callback(order_id, U128(requested)),
+        );
+        PromiseOrValue::Promise(promise)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for EcommerceContract {
+    /// Goi boi token contract sau khi user thuc hien `ft_transfer_call` toi contract nay.
+    /// `msg` la JSON payload { order_id, order_amount, seller_id } mo ta don hang dang duoc thanh toan.
+    /// Tra ve so FT chua dung het de token contract hoan lai cho nguoi gui.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_id = env::predecessor_account_id();
+        assert!(
+            self.allowed_token_ids.get(&token_id).unwrap_or(false),
+            "ERROR_TOKEN_NOT_ALLOWED"
+        );
+
+        let payload: FtPayOrderMsg =
+            serde_json::from_str(&msg).expect("ERROR_INVALID_FT_TRANSFER_MSG");
+
+        assert!(
+            amount.0 >= payload.order_amount.0,
+            "ERROR_DEPOSIT_NOT_ENOUGH"
+        );
+        assert!(
+            self.orders.get(&payload.order_id).is_none(),
+            "ERROR_ORDER_ALREADY_EXISTS"
+        );
+
+        let storage_usage_before = env::storage_usage();
+        let fee = payload.order_amount.0 * self.fee_bps as u128 / 10_000;
+
+        let order = Order {
+            order_id: payload.order_id.clone(),
+            payer_id: sender_id.clone(),
+            seller_id: payload.seller_id.clone(),
+            amount: payload.order_amount.0,
+            received_amount: amount.0,
+            fee,
+            refunded_amount: 0,
+            // Chup chinh sach hien tai, de set_fee_refundable() sau nay khong anh huong
+            // hoi to toi cac don hang da thanh toan tu truoc
+            fee_refundable: self.fee_refundable,
+            fee_settled: fee == 0,
+            status: OrderStatus::Pending,
+            created_at: env::block_timestamp(),
+            dispute_window_ns: DEFAULT_DISPUTE_WINDOW_NS,
+            token_id: Some(token_id.clone()),
+        };
+
+        self.orders.insert(&payload.order_id, &order);
+        self.index_order_by_payer(&sender_id, &payload.order_id);
+        self.charge_storage_cost(&payload.order_id, &sender_id, storage_usage_before);
+
+        // Phi nen tang (bang chinh loai FT nay) duoc chuyen cho platform ngay tai
+        // thoi diem thanh toan, khong cho den luc confirm_delivery/refund moi gui. Giong
+        // cac luong chuyen tien khac trong file nay, chain callback de cap nhat fee_settled
+        // thay vi fire-and-forget.
+        if fee > 0 {
+            let fee_promise = ext_fungible_token::ext(token_id)
+                .with_attached_deposit(1)
+                .with_static_gas(FT_TRANSFER_GAS)
+                .ft_transfer(self.fee_account_id.clone(), U128(fee), None);
+            fee_promise.then(
                 ext_self::ext(env::current_account_id())
                     .with_attached_deposit(0)
                     .with_static_gas(TRANSFER_GAS)
-                    .transfer_callback(order_id),
+                    .fee_callback(payload.order_id.clone()),
             );
-            PromiseOrValue::Promise(promise)
-        } else {
-            PromiseOrValue::Value(U128(0))
         }
+
+        // So FT vuot qua order_amount duoc token contract tu dong hoan lai cho sender
+        PromiseOrValue::Value(U128(amount.0 - payload.order_amount.0))
     }
 }
 
 #[near_bindgen]
 impl ExtEcommerceContract for EcommerceContract {
     #[private]
-    fn transfer_callback(&mut self, order_id: OrderId) -> PromiseOrValue<U128> {
+    fn transfer_callback(&mut self, order_id: OrderId, refunded_delta: U128) -> PromiseOrValue<U128> {
         assert_eq!(env::promise_results_count(), 1, "ERROR_TOO_MANY_RESULTS");
 
         match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Successful(value) => PromiseOrValue::Value(U128(0)),
+            PromiseResult::Successful(_value) => {
+                // Hoan tien thanh cong -> neu don hang da Refunded toan bo, CHI BAY GIO
+                // moi tra storage rent lai cho payer va xoa ban ghi. Lam viec nay truoc khi
+                // Promise chuyen tien chay xong se de payer rut storage credit cho bytes
+                // van con bi order (chua xoa) chiem, neu transfer roi that bai va phai rollback.
+                let order = self.orders.get(&order_id).expect("ERROR_ORDER_NOT_FOUND");
+                if order.status == OrderStatus::Refunded {
+                    self.release_storage_cost(&order_id, &order.payer_id);
+                    self.prune_order(&order_id, &order.payer_id);
+                }
+
+                PromiseOrValue::Value(U128(0))
+            }
             PromiseResult::Failed => {
-                // Cap nhat lai trang thai refund
+                // Chuyen tien that bai -> rollback dung phan da thu (refunded_delta),
+                // khong phai toan bo don hang, de cac lan refund truoc van giu nguyen
                 let mut order = self.orders.get(&order_id).expect("ERROR_ORDER_NOT_FOUND");
-                order.is_refund = false;
+                order.refunded_amount -= refunded_delta.0;
+                if order.status == OrderStatus::Refunded {
+                    order.status = OrderStatus::Pending;
+                }
+
+                self.orders.insert(&order_id, &order);
+
+                PromiseOrValue::Value(U128(refunded_delta.0))
+            }
+        }
+    }
+
+    #[private]
+    fn release_callback(&mut self, order_id: OrderId) -> PromiseOrValue<U128> {
+        assert_eq!(env::promise_results_count(), 1, "ERROR_TOO_MANY_RESULTS");
+
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_value) => PromiseOrValue::Value(U128(0)),
+            PromiseResult::Failed => {
+                // Giai ngan that bai -> dua don hang ve lai Pending de co the thu lai
+                let mut order = self.orders.get(&order_id).expect("ERROR_ORDER_NOT_FOUND");
+                order.status = OrderStatus::Pending;
 
                 self.orders.insert(&order_id, &order);
 
@@ -133,16 +590,32 @@ impl ExtEcommerceContract for EcommerceContract {
             }
         }
     }
+
+    // Ghi nhan ket qua cua Promise chuyen fee nen tang cho fee_account_id. Neu that bai,
+    // fee_settled van la false de lam bang chung fee chua thuc su toi noi (NEAR chuyen
+    // that bai se tu dong quay lai balance cua contract, khong mat tien, chi can cap nhat co).
+    #[private]
+    fn fee_callback(&mut self, order_id: OrderId) {
+        assert_eq!(env::promise_results_count(), 1, "ERROR_TOO_MANY_RESULTS");
+
+        if let PromiseResult::Successful(_value) = env::promise_result(0) {
+            if let Some(mut order) = self.orders.get(&order_id) {
+                order.fee_settled = true;
+                self.orders.insert(&order_id, &order);
+            }
+        }
+    }
 }
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod test {
+    use std::collections::HashMap;
     use std::task::Context;
 
     use super::*;
     use near_sdk::env::signer_account_id;
     use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::{testing_env, MockedBlockchain};
+    use near_sdk::{testing_env, MockedBlockchain, RuntimeFeesConfig, VMConfig};
 
     fn get_context(is_view: bool) -> VMContextBuilder {
         let mut builder = VMContextBuilder::new();
@@ -170,8 +643,10 @@ mod test {
 
         let mut contract = EcommerceContract::new(alice.clone());
         let order_amount = U128(1000);
+        let bob: AccountId = accounts(1);
 
-        contract.pay_order("order_1".to_owned(), order_amount);
+        deposit_storage(&mut context, &mut contract);
+        contract.pay_order("order_1".to_owned(), order_amount, bob.clone());
 
         let order = contract.get_order("order_1".to_owned());
 
@@ -179,7 +654,8 @@ mod test {
         assert_eq!(order.order_id, "order_1".to_owned());
         assert_eq!(order.amount, order_amount.0);
         assert_eq!(order.payer_id, alice);
-        assert!(order.is_completed);
+        assert_eq!(order.seller_id, bob);
+        assert_eq!(order.status, OrderStatus::Pending);
     }
 
     #[test]
@@ -198,7 +674,399 @@ mod test {
 
         let mut contract = EcommerceContract::new(alice.clone());
         let order_amount = U128(2000);
+        let bob: AccountId = accounts(1);
+
+        contract.pay_order("order_1".to_owned(), order_amount, bob);
+    }
+
+    // storage_byte_cost() la 10^19 yoctoNEAR/byte, nen storage balance phai du lon thi
+    // pay_order/ft_on_transfer moi tru duoc phi luu tru. Nap 1 NEAR cho predecessor roi
+    // tra lai attached_deposit cu cho cac lenh goi tiep theo.
+    fn deposit_storage(context: &mut VMContextBuilder, contract: &mut EcommerceContract) {
+        let attached_deposit = context.context.attached_deposit;
+        testing_env!(context.attached_deposit(10u128.pow(24)).build());
+        contract.storage_deposit(None);
+        testing_env!(context.attached_deposit(attached_deposit).build());
+    }
+
+    #[test]
+    fn test_refund_partial_accumulates() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(10_000)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        deposit_storage(&mut context, &mut contract);
+        contract.set_fee(1_000, alice.clone());
+        contract.pay_order("order_1".to_owned(), U128(1000), bob);
+
+        contract.refund("order_1".to_owned(), Some(U128(500)));
+        let order = contract.get_order("order_1".to_owned());
+        assert_eq!(order.refunded_amount, 500);
+        assert_eq!(order.status, OrderStatus::Pending);
+
+        // Phan con lai cua refundable_cap (amount - fee = 900) la 400
+        contract.refund("order_1".to_owned(), Some(U128(400)));
+        let order = contract.get_order("order_1".to_owned());
+        assert_eq!(order.refunded_amount, 900);
+        assert_eq!(order.status, OrderStatus::Refunded);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERROR_REFUND_EXCEEDS_REMAINING")]
+    fn test_refund_exceeds_remaining_panics() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(10_000)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        deposit_storage(&mut context, &mut contract);
+        contract.pay_order("order_1".to_owned(), U128(1000), bob);
+
+        contract.refund("order_1".to_owned(), Some(U128(1100)));
+    }
+
+    #[test]
+    fn test_refund_fee_refundable_returns_full_amount() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(1_000)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        deposit_storage(&mut context, &mut contract);
+        contract.set_fee(1_000, alice.clone());
+        contract.set_fee_refundable(true);
+        contract.pay_order("order_1".to_owned(), U128(1000), bob);
+
+        contract.refund("order_1".to_owned(), None);
+        let order = contract.get_order("order_1".to_owned());
+
+        // fee_refundable = true -> refundable_cap bao gom ca fee (1000), khong phai 900
+        assert_eq!(order.refunded_amount, 1000);
+        assert_eq!(order.status, OrderStatus::Refunded);
+    }
+
+    #[test]
+    fn test_transfer_callback_rollback_on_failed_refund() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(10_000)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        deposit_storage(&mut context, &mut contract);
+        contract.pay_order("order_1".to_owned(), U128(1000), bob);
+        contract.refund("order_1".to_owned(), Some(U128(1000)));
+
+        let order = contract.get_order("order_1".to_owned());
+        assert_eq!(order.status, OrderStatus::Refunded);
+        assert_eq!(order.refunded_amount, 1000);
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+
+        contract.transfer_callback("order_1".to_owned(), U128(1000));
+
+        let order = contract.get_order("order_1".to_owned());
+        assert_eq!(order.status, OrderStatus::Pending);
+        assert_eq!(order.refunded_amount, 0);
+    }
+
+    #[test]
+    fn test_release_callback_rollback_on_failed_delivery() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(10_000)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        deposit_storage(&mut context, &mut contract);
+        contract.pay_order("order_1".to_owned(), U128(1000), bob);
+        contract.confirm_delivery("order_1".to_owned());
+
+        let order = contract.get_order("order_1".to_owned());
+        assert_eq!(order.status, OrderStatus::Released);
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+
+        contract.release_callback("order_1".to_owned());
+
+        let order = contract.get_order("order_1".to_owned());
+        assert_eq!(order.status, OrderStatus::Pending);
+    }
+
+    #[test]
+    fn test_get_orders_pagination() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(100)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        deposit_storage(&mut context, &mut contract);
+        contract.pay_order("order_1".to_owned(), U128(100), bob.clone());
+        contract.pay_order("order_2".to_owned(), U128(100), bob.clone());
+        contract.pay_order("order_3".to_owned(), U128(100), bob);
+
+        assert_eq!(contract.get_orders(U64(0), U64(2)).len(), 2);
+        assert_eq!(contract.get_orders(U64(2), U64(10)).len(), 1);
+        assert!(contract.get_orders(U64(10), U64(10)).is_empty());
+    }
+
+    #[test]
+    fn test_get_orders_by_payer_pagination() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(100)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        deposit_storage(&mut context, &mut contract);
+        contract.pay_order("order_1".to_owned(), U128(100), bob.clone());
+        contract.pay_order("order_2".to_owned(), U128(100), bob.clone());
+        contract.pay_order("order_3".to_owned(), U128(100), bob.clone());
+
+        assert_eq!(
+            contract.get_orders_by_payer(alice.clone(), U64(0), U64(2)).len(),
+            2
+        );
+        assert_eq!(
+            contract.get_orders_by_payer(alice.clone(), U64(2), U64(10)).len(),
+            1
+        );
+        assert!(contract
+            .get_orders_by_payer(alice.clone(), U64(10), U64(10))
+            .is_empty());
+        assert!(contract
+            .get_orders_by_payer(bob, U64(0), U64(10))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_ft_on_transfer_happy_path_refunds_overpayment() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+        let token: AccountId = accounts(2);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        contract.set_token_allowed(token.clone(), true);
+        deposit_storage(&mut context, &mut contract);
+
+        context.predecessor_account_id(token.clone());
+        testing_env!(context.build());
+
+        let msg = serde_json::to_string(&FtPayOrderMsg {
+            order_id: "order_1".to_owned(),
+            order_amount: U128(1000),
+            seller_id: bob.clone(),
+        })
+        .unwrap();
+
+        let unused = match contract.ft_on_transfer(alice.clone(), U128(1200), msg) {
+            PromiseOrValue::Value(value) => value.0,
+            PromiseOrValue::Promise(_) => panic!("expected an immediate value"),
+        };
+        assert_eq!(unused, 200);
+
+        let order = contract.get_order("order_1".to_owned());
+        assert_eq!(order.amount, 1000);
+        assert_eq!(order.payer_id, alice);
+        assert_eq!(order.seller_id, bob);
+        assert_eq!(order.token_id, Some(token));
+        assert_eq!(order.status, OrderStatus::Pending);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERROR_TOKEN_NOT_ALLOWED")]
+    fn test_ft_on_transfer_rejects_unlisted_token() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+        let token: AccountId = accounts(2);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(token)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice);
+
+        let msg = serde_json::to_string(&FtPayOrderMsg {
+            order_id: "order_1".to_owned(),
+            order_amount: U128(1000),
+            seller_id: bob,
+        })
+        .unwrap();
+
+        contract.ft_on_transfer(accounts(3), U128(1000), msg);
+    }
+
+    #[test]
+    fn test_claim_timeout_refund_after_window_expires() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(10_000)
+            .signer_account_id(alice.clone())
+            .block_timestamp(0);
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        deposit_storage(&mut context, &mut contract);
+        contract.pay_order("order_1".to_owned(), U128(1000), bob);
+
+        context.block_timestamp(DEFAULT_DISPUTE_WINDOW_NS + 1);
+        testing_env!(context.build());
+
+        contract.claim_timeout_refund("order_1".to_owned());
+
+        let order = contract.get_order("order_1".to_owned());
+        assert_eq!(order.status, OrderStatus::Refunded);
+        assert_eq!(order.refunded_amount, 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERROR_DISPUTE_WINDOW_NOT_EXPIRED")]
+    fn test_claim_timeout_refund_before_window_expires_panics() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+        let bob: AccountId = accounts(1);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(10_000)
+            .signer_account_id(alice.clone())
+            .block_timestamp(0);
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        deposit_storage(&mut context, &mut contract);
+        contract.pay_order("order_1".to_owned(), U128(1000), bob);
+
+        contract.claim_timeout_refund("order_1".to_owned());
+    }
+
+    #[test]
+    fn test_storage_withdraw_returns_unused_balance() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(500)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice.clone());
+        contract.storage_deposit(None);
+
+        let remaining = contract.storage_withdraw(Some(U128(100)));
+        assert_eq!(remaining.0, 400);
+        assert_eq!(contract.storage_balance_of(alice).0, 400);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERROR_STORAGE_NOT_REGISTERED")]
+    fn test_storage_withdraw_without_deposit_panics() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice);
+        contract.storage_withdraw(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERROR_STORAGE_BALANCE_NOT_ENOUGH")]
+    fn test_storage_withdraw_more_than_balance_panics() {
+        let mut context = get_context(false);
+        let alice: AccountId = accounts(0);
+
+        context
+            .account_balance(10_000)
+            .predecessor_account_id(alice.clone())
+            .attached_deposit(100)
+            .signer_account_id(alice.clone());
+        testing_env!(context.build());
+
+        let mut contract = EcommerceContract::new(alice);
+        contract.storage_deposit(None);
 
-        contract.pay_order("order_1".to_owned(), order_amount);
+        contract.storage_withdraw(Some(U128(200)));
     }
 }
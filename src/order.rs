@@ -0,0 +1,51 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{AccountId, Balance};
+
+use crate::OrderId;
+
+// Trang thai cua mot don hang dang trong escrow
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OrderStatus {
+    // Tien dang giu trong contract, cho nguoi mua xac nhan hoac het han tranh chap
+    Pending,
+    // Tien da duoc giai ngan cho nguoi ban
+    Released,
+    // Tien da duoc hoan lai cho nguoi mua
+    Refunded,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Order {
+    pub order_id: OrderId,
+    pub payer_id: AccountId,
+    pub seller_id: AccountId,
+    pub amount: Balance,
+    pub received_amount: Balance,
+    // Phi nen tang cho platform, tinh tu amount theo fee_bps tai thoi diem pay_order
+    pub fee: Balance,
+    // Tong so da duoc refund cho payer, cong don qua nhieu lan refund() mot phan
+    pub refunded_amount: Balance,
+    // Chup lai chinh sach fee_refundable cua contract tai thoi diem pay_order, de sau nay
+    // owner doi set_fee_refundable() khong lam thay doi hoi to cac don hang dang cho
+    pub fee_refundable: bool,
+    // Fee da thuc su chuyen toi fee_account_id thanh cong hay chua (Promise co the that bai)
+    pub fee_settled: bool,
+    pub status: OrderStatus,
+    pub created_at: u64,
+    // Khoang thoi gian (ns) nguoi mua co the tranh chap truoc khi ai cung co the claim refund
+    pub dispute_window_ns: u64,
+    // None nghia la thanh toan bang native NEAR, Some(token_id) la FT contract da chuyen tien
+    pub token_id: Option<AccountId>,
+}
+
+// Payload duoc gui kem trong `msg` cua `ft_transfer_call`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtPayOrderMsg {
+    pub order_id: OrderId,
+    pub order_amount: near_sdk::json_types::U128,
+    pub seller_id: AccountId,
+}